@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper::body::Incoming;
@@ -6,21 +6,40 @@ use hyper::service::service_fn;
 use hyper_util::rt::TokioExecutor;
 use hyper_util::server::conn::auto::Builder as AutoBuilder;
 use http_body_util::{Full, BodyExt};
-use std::{convert::Infallible, net::SocketAddr};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::Duration,
+};
 use tokio::net::TcpListener;
 use tokio::select;
 
 // Wasmtime (Component Model / WASI Preview2)
-use wasmtime::{Config, Engine, component::{Component, Linker}};
+use wasmtime::{Config, Engine, Trap, component::{Component, Linker}};
 use wasmtime::Store;
 use wasmtime_wasi::{WasiCtx, WasiCtxBuilder, WasiView};
 use wasmtime_wasi::p2::bindings::Command;
 use wasmtime::component::ResourceTable;
 
+// wasi:http 向け（デプロイ済みコンポーネントを incoming-handler として呼び出すモード）
+use wasmtime_wasi_http::bindings::http::types::Scheme;
+use wasmtime_wasi_http::bindings::ProxyPre;
+use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
+
+// cache/decompress/invocation/limits/report は Component と Core Module の両バイナリで
+// 共有するロジックなので `wasm_common` クレートに切り出してある
+use wasm_common::cache::{self, CompileCache};
+use wasm_common::decompress::{decode_body, BodyTooLarge};
+use wasm_common::invocation::{parse_invocation, Invocation};
+use wasm_common::report::{classify, ExecutionReport, GuestFailure};
+use wasm_common::limits::Limits;
+
 #[derive(Default)]
 struct Ctx {
     wasi: WasiCtx,
     table: ResourceTable,
+    limits: Limits,
 }
 impl WasiView for Ctx {
     fn ctx(&mut self) -> wasmtime_wasi::WasiCtxView<'_> {
@@ -31,51 +50,195 @@ impl WasiView for Ctx {
     }
 }
 
+// `/invoke/:id` で wasi:http/proxy として呼び出す側のコンテキスト
+#[derive(Default)]
+struct HttpCtx {
+    wasi: WasiCtx,
+    http: WasiHttpCtx,
+    table: ResourceTable,
+    limits: Limits,
+}
+impl WasiView for HttpCtx {
+    fn ctx(&mut self) -> wasmtime_wasi::WasiCtxView<'_> {
+        wasmtime_wasi::WasiCtxView {
+            ctx: &mut self.wasi,
+            table: &mut self.table,
+        }
+    }
+}
+impl WasiHttpView for HttpCtx {
+    fn ctx(&mut self) -> &mut WasiHttpCtx {
+        &mut self.http
+    }
+}
+
+// 実行予算（epoch / fuel）の既定値。env で上書き可能
+const DEFAULT_EPOCH_TICK_MS: u64 = 10;
+const DEFAULT_EPOCH_DEADLINE_MS: u64 = 5_000;
+
+fn env_u64(key: &str, default: u64) -> u64 {
+    std::env::var(key).ok().and_then(|s| s.parse().ok()).unwrap_or(default)
+}
+
+// POST /deploy で受け取った component を保持しておき、`/invoke/:id/...` から
+// wasi:http/proxy の incoming-handler として呼び出せるようにする
+struct DeployedComponent {
+    pre: ProxyPre<HttpCtx>,
+}
+
+// `deployed` を無制限に増やし続けるとメモリを食いつぶすので、他のキャッシュと同じ
+// CompileCache の LRU で件数に上限を設ける（byte weight は使わないので weight は常に 1、
+// max_bytes は usize::MAX にして件数上限だけが効くようにする）
+type DeployedRegistry = CompileCache<String, Arc<DeployedComponent>>;
+
+struct AppState {
+    engine: Engine,
+    // 燃料計測 (consume_fuel) は Engine 単位の設定なので、X-Wasm-Fuel 付きリクエスト専用に
+    // 別の Engine + キャッシュを常駐させておく。使い捨てにすると毎回フルコンパイルし直しになる
+    fuel_engine: Engine,
+    fuel_component_cache: CompileCache<[u8; 32], Component>,
+    next_id: AtomicU64,
+    deployed: DeployedRegistry,
+    component_cache: CompileCache<[u8; 32], Component>,
+}
+
+impl AppState {
+    fn new() -> Result<Self> {
+        let mut cfg = Config::new();
+        cfg.wasm_component_model(true).async_support(true);
+        cfg.epoch_interruption(true);
+
+        let mut fuel_cfg = Config::new();
+        fuel_cfg.wasm_component_model(true).async_support(true);
+        fuel_cfg.epoch_interruption(true);
+        fuel_cfg.consume_fuel(true);
+
+        let max_entries = env_u64("WASM_CACHE_MAX_ENTRIES", 64) as usize;
+        let max_bytes = env_u64("WASM_CACHE_MAX_BYTES", 256 * 1024 * 1024) as usize;
+        let max_deployed = env_u64("WASM_MAX_DEPLOYED_COMPONENTS", 256) as usize;
+        Ok(Self {
+            engine: Engine::new(&cfg)?,
+            fuel_engine: Engine::new(&fuel_cfg)?,
+            fuel_component_cache: CompileCache::new(max_entries, max_bytes),
+            next_id: AtomicU64::new(1),
+            deployed: CompileCache::new(max_deployed, usize::MAX),
+            component_cache: CompileCache::new(max_entries, max_bytes),
+        })
+    }
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
     let host = std::env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
     let port: u16 = std::env::var("PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(3000);
     let addr: SocketAddr = format!("{}:{}", host, port).parse().expect("invalid HOST/PORT");
 
+    let state = Arc::new(AppState::new()?);
+
     let listener = TcpListener::bind(addr).await?;
     println!("listening on http://{}", addr);
 
+    // epoch を wall-clock にマッピングする tick タスク。プロセス全体で一つだけ動かす
+    // (通常用・燃料計測用の両方の Engine をまとめて進める)
+    let tick_ms = env_u64("WASM_EPOCH_TICK_MS", DEFAULT_EPOCH_TICK_MS).max(1);
+    let ticker_engine = state.engine.clone();
+    let ticker_fuel_engine = state.fuel_engine.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(tick_ms));
+        loop {
+            interval.tick().await;
+            ticker_engine.increment_epoch();
+            ticker_fuel_engine.increment_epoch();
+        }
+    });
+
+    // Ctrl+C で新規受付を止めた後、既存接続へ graceful shutdown を知らせるための合図
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let mut conns = tokio::task::JoinSet::new();
+
     use hyper_util::rt::TokioIo;
     select! {
-        _ = async {
+        res = async {
             loop {
                 let (io, _peer) = listener.accept().await?;
-                tokio::spawn(async move {
-                    let svc = service_fn(router);
+                let state = state.clone();
+                let mut shutdown_rx = shutdown_rx.clone();
+                conns.spawn(async move {
+                    let svc = service_fn(move |req| router(state.clone(), req));
                     let io = TokioIo::new(io);
                     let builder = AutoBuilder::new(TokioExecutor::new());
                     let conn = builder.serve_connection(io, svc);
-                    if let Err(e) = conn.await {
-                        eprintln!("server error: {e}");
+                    tokio::pin!(conn);
+                    tokio::select! {
+                        res = conn.as_mut() => {
+                            if let Err(e) = res {
+                                eprintln!("server error: {e}");
+                            }
+                        }
+                        _ = shutdown_rx.changed() => {
+                            conn.as_mut().graceful_shutdown();
+                            if let Err(e) = conn.await {
+                                eprintln!("server error during graceful shutdown: {e}");
+                            }
+                        }
                     }
                 });
             }
+            #[allow(unreachable_code)]
             Ok::<(), anyhow::Error>(())
-        } =>{},
+        } => {
+            res?;
+        },
         _ = tokio::signal::ctrl_c() => {
-            eprintln!("Ctrl+C received. stopping the servet");
+            eprintln!("Ctrl+C received. draining in-flight connections...");
         }
     }
+
+    // 新規接続の受付はここで止まっている。既存接続には graceful shutdown を通知し、
+    // drain timeout まで待ってから終了する（待ちきれない分は打ち切る）
+    let _ = shutdown_tx.send(true);
+    let drain_timeout = Duration::from_millis(env_u64("SHUTDOWN_DRAIN_TIMEOUT_MS", 30_000));
+    if tokio::time::timeout(drain_timeout, async {
+        while conns.join_next().await.is_some() {}
+    })
+    .await
+    .is_err()
+    {
+        eprintln!("drain timeout exceeded; aborting {} remaining connection(s)", conns.len());
+        conns.shutdown().await;
+    }
+
     Ok(())
 }
 
-async fn router(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+async fn router(state: Arc<AppState>, req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
     let method = req.method().clone();
     let path = req.uri().path().to_string();
 
-    let resp = match (method, path.as_str()) {
-        (Method::GET, "/") => {
-            ok_text("OK: POST /execute-wasm (body = WASI Preview2 component)")
+    let resp = match (&method, path.as_str()) {
+        (&Method::GET, "/") => {
+            ok_text("OK: POST /execute-wasm (body = WASI Preview2 component), POST /deploy + /invoke/:id (wasi:http/proxy)")
         }
-        (Method::POST, "/execute-wasm") => {
-            match handle_execute_wasm(req).await {
-                Ok(text) => ok_text(text),
-                Err(e)   => err_text(StatusCode::BAD_REQUEST, format!("WASM error: {e}")),
+        (&Method::POST, "/execute-wasm") => {
+            let wants_json = accepts_json(&req);
+            match handle_execute_wasm(&state, req).await {
+                Ok((status, report, cache_status)) => execution_response(status, report, cache_status, wants_json),
+                Err(e)   => execution_error_response(&e),
+            }
+        }
+        (&Method::POST, "/deploy") => {
+            match handle_deploy(&state, req).await {
+                Ok(id) => ok_text(format!("deployed: {id}")),
+                Err(e) if e.downcast_ref::<BodyTooLarge>().is_some() => {
+                    err_text(StatusCode::PAYLOAD_TOO_LARGE, format!("deploy error: {e}"))
+                }
+                Err(e) => err_text(StatusCode::BAD_REQUEST, format!("deploy error: {e}")),
+            }
+        }
+        _ if path.starts_with("/invoke/") => {
+            match handle_invoke(&state, req).await {
+                Ok(resp) => resp,
+                Err(e)   => invoke_error_response(&e),
             }
         }
         _ => err_text(StatusCode::NOT_FOUND, "not found"),
@@ -99,39 +262,284 @@ fn err_text<S: Into<String>>(code: StatusCode, s: S) -> Response<Full<Bytes>> {
         .unwrap()
 }
 
-async fn handle_execute_wasm(req: Request<Incoming>) -> Result<String> {
-    // リクエストボディを全部読み込み（Hyper 1.x では BodyExt::collect → to_bytes）
-    let bytes = req.into_body().collect().await?.to_bytes();
+fn accepts_json(req: &Request<Incoming>) -> bool {
+    req.headers()
+        .get(hyper::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"))
+}
 
-    // Wasmtime エンジン（Component Model + async）
-    let mut cfg = Config::new();
-    cfg.wasm_component_model(true).async_support(true);
-    let engine = Engine::new(&cfg)?;
+// Accept: application/json なら構造化した実行結果を、それ以外は従来どおりの素のテキストを返す
+fn execution_response(
+    status: StatusCode,
+    report: ExecutionReport,
+    cache_status: &str,
+    wants_json: bool,
+) -> Response<Full<Bytes>> {
+    if wants_json {
+        let body = serde_json::to_vec(&report).expect("ExecutionReport always serializes");
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .header("x-cache", cache_status)
+            .body(Full::from(Bytes::from(body)))
+            .unwrap()
+    } else {
+        let text = match &report.error {
+            Some(e) => format!("WASM error: {e}"),
+            None if report.exit_code == 0 => "component finished successfully".to_string(),
+            None => "component finished with error".to_string(),
+        };
+        Response::builder()
+            .status(status)
+            .header("content-type", "text/plain; charset=utf-8")
+            .header("x-cache", cache_status)
+            .body(Full::from(Bytes::from(text)))
+            .unwrap()
+    }
+}
+
+// epoch 割り込み／燃料切れは「ただのバグ」とは違うので、普通の trap とは別のステータスで
+// 区別できるようにする。`default` はどの trap にも当てはまらない場合のステータス
+// (呼び出し元ごとに意味が違う: /execute-wasm は 400、/invoke は 502)
+fn trap_status(e: &anyhow::Error, default: StatusCode) -> (StatusCode, String) {
+    if let Some(e) = e.downcast_ref::<BodyTooLarge>() {
+        return (StatusCode::PAYLOAD_TOO_LARGE, e.to_string());
+    }
+    match e.root_cause().downcast_ref::<Trap>() {
+        Some(Trap::Interrupt) => (StatusCode::GATEWAY_TIMEOUT, format!("execution deadline exceeded: {e}")),
+        Some(Trap::OutOfFuel) => (StatusCode::REQUEST_TIMEOUT, format!("fuel budget exhausted: {e}")),
+        _ => (default, e.to_string()),
+    }
+}
+
+fn execution_error_response(e: &anyhow::Error) -> Response<Full<Bytes>> {
+    let (status, msg) = trap_status(e, StatusCode::BAD_REQUEST);
+    err_text(status, format!("WASM error: {msg}"))
+}
+
+// `/invoke/:id` は handle_invoke が仕込んだ epoch deadline を超えた場合も同じ trap として
+// trap する。その場合は普通の guest エラーと区別できるよう、execution_error_response と
+// 同じ trap 分類を通す（デフォルトは proxy らしく 502 のまま）
+fn invoke_error_response(e: &anyhow::Error) -> Response<Full<Bytes>> {
+    let (status, msg) = trap_status(e, StatusCode::BAD_GATEWAY);
+    err_text(status, format!("invoke error: {msg}"))
+}
+
+async fn handle_execute_wasm(
+    state: &AppState,
+    req: Request<Incoming>,
+) -> Result<(StatusCode, ExecutionReport, &'static str)> {
+    // X-Wasm-Fuel: このリクエスト限りの命令数上限（未指定なら env の既定値、それも無ければ無制限）
+    let fuel_limit = req
+        .headers()
+        .get("x-wasm-fuel")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+        .or_else(|| {
+            let v = env_u64("WASM_FUEL_DEFAULT", 0);
+            if v > 0 { Some(v) } else { None }
+        });
 
-    // 受け取った .wasm は「WASI Preview2 component」を想定
-    let component = Component::from_binary(&engine, &bytes)?;
+    // 非 multipart なら本文全体を wasm として扱う（Content-Encoding があれば展開）。
+    // multipart/form-data なら module/stdin/args/env の各パートを読み取る
+    let max_decompressed = env_u64("WASM_MAX_DECOMPRESSED_BYTES", 64 * 1024 * 1024) as usize;
+    let Invocation { wasm: bytes, stdin, args, env } = parse_invocation(req, max_decompressed).await?;
 
+    let deadline_ms = env_u64("WASM_EPOCH_DEADLINE_MS", DEFAULT_EPOCH_DEADLINE_MS);
+    let tick_ms = env_u64("WASM_EPOCH_TICK_MS", DEFAULT_EPOCH_TICK_MS).max(1);
+    let deadline_ticks = (deadline_ms / tick_ms).max(1);
+
+    if fuel_limit.is_some() {
+        // 燃料消費の計測は Store 単位の設定だが、計測を有効にした Engine でコンパイルした
+        // Component しか使えない。専用の常駐 Engine + キャッシュを使い、毎回のフルコンパイルを避ける
+        let key = cache::content_hash(&bytes);
+        let (component, cache_status) = match state.fuel_component_cache.get(&key) {
+            Some(component) => (component, "hit"),
+            None => {
+                let serialized = state.fuel_engine.precompile_component(&bytes)?;
+                let weight = serialized.len();
+                let component = unsafe { Component::deserialize(&state.fuel_engine, &serialized)? };
+                state.fuel_component_cache.insert(key, component.clone(), weight);
+                (component, "miss")
+            }
+        };
+        let (status, report) =
+            run_component(&state.fuel_engine, &component, deadline_ticks, fuel_limit, &stdin, &args, &env).await?;
+        Ok((status, report, cache_status))
+    } else {
+        let key = cache::content_hash(&bytes);
+        let (component, cache_status) = match state.component_cache.get(&key) {
+            Some(component) => (component, "hit"),
+            None => {
+                // コンパイルを一度だけ行い、そのシリアライズ済みサイズをキャッシュの重みに使う
+                let serialized = state.engine.precompile_component(&bytes)?;
+                let weight = serialized.len();
+                let component = unsafe { Component::deserialize(&state.engine, &serialized)? };
+                state.component_cache.insert(key, component.clone(), weight);
+                (component, "miss")
+            }
+        };
+        let (status, report) = run_component(&state.engine, &component, deadline_ticks, fuel_limit, &stdin, &args, &env).await?;
+        Ok((status, report, cache_status))
+    }
+}
+
+async fn run_component(
+    engine: &Engine,
+    component: &Component,
+    deadline_ticks: u64,
+    fuel_limit: Option<u64>,
+    stdin: &[u8],
+    args: &[String],
+    env: &[(String, String)],
+) -> Result<(StatusCode, ExecutionReport)> {
     // Linker に WASI P2 を追加（async 版）
-    let mut linker: Linker<Ctx> = Linker::new(&engine);
-    wasmtime_wasi::p2::add_to_linker_async(&mut linker)?; // sync 版もあり。用途で選択。 [oai_citation:5‡Wasmtime](https://docs.wasmtime.dev/api/wasmtime_wasi/p2/fn.add_to_linker_sync.html?utm_source=chatgpt.com)
+    let mut linker: Linker<Ctx> = Linker::new(engine);
+    wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
+
+    let stdout_pipe = wasmtime_wasi::p2::pipe::MemoryOutputPipe::new(1024 * 1024);
+    let stderr_pipe = wasmtime_wasi::p2::pipe::MemoryOutputPipe::new(1024 * 1024);
+    let stdout_reader = stdout_pipe.clone();
+    let stderr_reader = stderr_pipe.clone();
 
-    // 実行コンテキスト（stdio/args などは適宜調整）
+    // 呼び出し元が渡した stdin/args/env をそのまま使う。ホストの環境は渡さない
     let wasi = WasiCtxBuilder::new()
-        .inherit_stdio()
-        .inherit_args()
+        .stdin(wasmtime_wasi::p2::pipe::MemoryInputPipe::new(stdin.to_vec()))
+        .args(args)
+        .envs(env)
+        .stdout(stdout_pipe)
+        .stderr(stderr_pipe)
         .build();
 
-    let mut store = Store::new(&engine, Ctx { 
+    let mut store = Store::new(engine, Ctx {
         wasi,
         table: ResourceTable::new(),
+        limits: Limits::from_env(),
     });
+    store.set_epoch_deadline(deadline_ticks);
+    if let Some(limit) = fuel_limit {
+        store.set_fuel(limit)?;
+    }
+    store.limiter(|ctx| &mut ctx.limits);
 
+    let start = std::time::Instant::now();
     // `wasi:cli/command` の run() を呼ぶ
-    let cmd = Command::instantiate_async(&mut store, &component, &linker).await?;
-    let result = cmd.wasi_cli_run().call_run(&mut store).await?;
+    let result: Result<()> = async {
+        let cmd = Command::instantiate_async(&mut store, component, &linker).await?;
+        match cmd.wasi_cli_run().call_run(&mut store).await? {
+            Ok(()) => Ok(()),
+            Err(()) => Err(GuestFailure.into()),
+        }
+    }
+    .await;
+    let duration_ms = start.elapsed().as_millis() as u64;
+    let fuel_consumed = fuel_limit.map(|limit| limit.saturating_sub(store.get_fuel().unwrap_or(0)));
+    let peak_memory_bytes = store.data().limits.peak_memory_bytes();
 
-    match result {
-        Ok(()) => Ok("component finished successfully".to_string()),
-        Err(()) => Ok("component finished with error".to_string()),
+    Ok(classify(
+        result,
+        stdout_reader.contents().to_vec(),
+        stderr_reader.contents().to_vec(),
+        fuel_consumed,
+        duration_ms,
+        peak_memory_bytes,
+    ))
+}
+
+// POST /deploy: component をコンパイルして wasi:http/proxy として pre-instantiate しておく。
+// 戻り値の id を `/invoke/:id/...` に使う
+async fn handle_deploy(state: &AppState, req: Request<Incoming>) -> Result<String> {
+    let encoding = req
+        .headers()
+        .get(hyper::header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let max_decompressed = env_u64("WASM_MAX_DECOMPRESSED_BYTES", 64 * 1024 * 1024) as usize;
+
+    let raw = req.into_body().collect().await?.to_bytes();
+    let bytes = decode_body(encoding.as_deref(), raw, max_decompressed).await?;
+    let component = Component::from_binary(&state.engine, &bytes)?;
+
+    let mut linker: Linker<HttpCtx> = Linker::new(&state.engine);
+    wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
+    wasmtime_wasi_http::add_to_linker_async(&mut linker)?;
+
+    let pre = ProxyPre::new(linker.instantiate_pre(&component)?)?;
+
+    let id = state.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+    state.deployed.insert(id.clone(), Arc::new(DeployedComponent { pre }), 1);
+    Ok(id)
+}
+
+// /invoke/:id/... を、デプロイ済み component の wasi:http/proxy incoming-handler に渡す
+async fn handle_invoke(state: &AppState, req: Request<Incoming>) -> Result<Response<Full<Bytes>>> {
+    let path = req.uri().path().to_string();
+    let rest = path.strip_prefix("/invoke/").unwrap_or("");
+    let (id, guest_path) = match rest.split_once('/') {
+        Some((id, rest)) => (id, format!("/{rest}")),
+        None => (rest, "/".to_string()),
+    };
+
+    let deployed = state
+        .deployed
+        .get(&id.to_string())
+        .ok_or_else(|| anyhow!("no component deployed under id {id}"))?;
+
+    // scheme と authority は out-of-band で明示しないと guest 側が誤動作する。
+    // authority は URI から取り、無ければ Host ヘッダを使う。どちらも無ければエラーにする
+    let scheme = Scheme::Http;
+    let authority = req
+        .uri()
+        .authority()
+        .map(|a| a.to_string())
+        .or_else(|| {
+            req.headers()
+                .get(hyper::header::HOST)
+                .and_then(|h| h.to_str().ok())
+                .map(|s| s.to_string())
+        })
+        .ok_or_else(|| anyhow!("request has no URI authority and no Host header"))?;
+
+    let (mut parts, body) = req.into_parts();
+    parts.uri = hyper::Uri::builder()
+        .path_and_query(guest_path)
+        .build()
+        .unwrap_or(parts.uri);
+    // Host ヘッダが無い（authority が URI 由来だった）場合も guest から見えるように補う
+    parts.headers.insert(hyper::header::HOST, authority.parse()?);
+    let guest_req = Request::from_parts(parts, body);
+
+    let mut store = Store::new(&state.engine, HttpCtx::default());
+    store.set_epoch_deadline((env_u64("WASM_EPOCH_DEADLINE_MS", DEFAULT_EPOCH_DEADLINE_MS)
+        / env_u64("WASM_EPOCH_TICK_MS", DEFAULT_EPOCH_TICK_MS).max(1))
+        .max(1));
+    store.limiter(|ctx| &mut ctx.limits);
+
+    let incoming = store.data_mut().new_incoming_request(scheme, guest_req)?;
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    let outparam = store.data_mut().new_response_outparam(sender)?;
+
+    let proxy = deployed.pre.instantiate_async(&mut store).await?;
+    let handle = tokio::spawn(async move {
+        proxy
+            .wasi_http_incoming_handler()
+            .call_handle(&mut store, incoming, outparam)
+            .await
+    });
+
+    match receiver.await {
+        Ok(Ok(resp)) => {
+            handle.await??;
+            let (parts, body) = resp.into_parts();
+            let bytes = body.collect().await?.to_bytes();
+            Ok(Response::from_parts(parts, Full::from(bytes)))
+        }
+        Ok(Err(e)) => Err(anyhow!("guest returned an error response: {e:?}")),
+        Err(_) => {
+            // guest が ResponseOutparam を使わずに終了した場合は handle 側のエラーを拾う
+            handle.await??;
+            Err(anyhow!("guest did not produce a response"))
+        }
     }
 }
@@ -0,0 +1,131 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::Mutex;
+
+/// 任意のキー `K` で引ける LRU キャッシュ。元々はコンパイル済み Module/Component を
+/// content hash (blake3) で引くためのものだったが、件数だけで上限を掛けたいレジストリ
+/// （例: デプロイ済み wasi:http/proxy コンポーネントを文字列 id で引く場合。その場合は
+/// `max_bytes` に `usize::MAX` を渡して件数上限だけを効かせる）にも流用できるよう、
+/// キー型を汎用化してある。`T` は `Module`/`Component`/`Arc<...>` を想定していて、
+/// どれも内部で reference-counted なので clone は安い
+pub struct CompileCache<K, T> {
+    inner: Mutex<Inner<K, T>>,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+struct Inner<K, T> {
+    entries: HashMap<K, (T, usize)>,
+    // 先頭が least-recently-used
+    order: VecDeque<K>,
+    total_bytes: usize,
+}
+
+impl<K: Eq + Hash + Clone, T: Clone> CompileCache<K, T> {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                total_bytes: 0,
+            }),
+            max_entries,
+            max_bytes,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<T> {
+        let mut inner = self.inner.lock().unwrap();
+        let value = inner.entries.get(key).map(|(v, _)| v.clone())?;
+        inner.order.retain(|k| k != key);
+        inner.order.push_back(key.clone());
+        Some(value)
+    }
+
+    pub fn insert(&self, key: K, value: T, weight: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.contains_key(&key) {
+            return;
+        }
+        inner.entries.insert(key.clone(), (value, weight));
+        inner.order.push_back(key);
+        inner.total_bytes += weight;
+
+        while inner.order.len() > self.max_entries || inner.total_bytes > self.max_bytes {
+            let Some(oldest) = inner.order.pop_front() else { break };
+            if let Some((_, w)) = inner.entries.remove(&oldest) {
+                inner.total_bytes -= w;
+            }
+        }
+    }
+}
+
+pub fn content_hash(bytes: &[u8]) -> [u8; 32] {
+    blake3::hash(bytes).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u8) -> [u8; 32] {
+        [n; 32]
+    }
+
+    #[test]
+    fn evicts_least_recently_used_when_over_max_entries() {
+        let cache = CompileCache::new(2, usize::MAX);
+        cache.insert(key(1), "a", 1);
+        cache.insert(key(2), "b", 1);
+        cache.insert(key(3), "c", 1);
+
+        assert!(cache.get(&key(1)).is_none());
+        assert_eq!(cache.get(&key(2)), Some("b"));
+        assert_eq!(cache.get(&key(3)), Some("c"));
+    }
+
+    #[test]
+    fn get_refreshes_recency_so_entry_survives_eviction() {
+        let cache = CompileCache::new(2, usize::MAX);
+        cache.insert(key(1), "a", 1);
+        cache.insert(key(2), "b", 1);
+        cache.get(&key(1)); // touch 1, making 2 the least-recently-used
+        cache.insert(key(3), "c", 1);
+
+        assert_eq!(cache.get(&key(1)), Some("a"));
+        assert!(cache.get(&key(2)).is_none());
+    }
+
+    #[test]
+    fn evicts_when_max_bytes_exceeded_even_under_max_entries() {
+        let cache = CompileCache::new(100, 10);
+        cache.insert(key(1), "a", 6);
+        cache.insert(key(2), "b", 6);
+
+        assert!(cache.get(&key(1)).is_none());
+        assert_eq!(cache.get(&key(2)), Some("b"));
+    }
+
+    #[test]
+    fn inserting_an_existing_key_is_a_no_op() {
+        let cache = CompileCache::new(100, usize::MAX);
+        cache.insert(key(1), "a", 1);
+        cache.insert(key(1), "b", 1);
+
+        assert_eq!(cache.get(&key(1)), Some("a"));
+    }
+
+    #[test]
+    fn string_keyed_cache_evicts_by_count_only_when_max_bytes_is_unbounded() {
+        // this is the shape DeployedRegistry uses: string ids, weight always 1,
+        // max_bytes = usize::MAX so only max_entries matters
+        let cache = CompileCache::new(2, usize::MAX);
+        cache.insert("a".to_string(), 1, 1);
+        cache.insert("b".to_string(), 2, 1);
+        cache.insert("c".to_string(), 3, 1);
+
+        assert!(cache.get(&"a".to_string()).is_none());
+        assert_eq!(cache.get(&"b".to_string()), Some(2));
+        assert_eq!(cache.get(&"c".to_string()), Some(3));
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Result};
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder};
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+
+/// decompression bomb 対策で展開上限を超えたときのエラー。413 に対応付けるための目印
+#[derive(Debug)]
+pub struct BodyTooLarge {
+    pub limit: usize,
+}
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "decompressed body exceeds limit of {} bytes", self.limit)
+    }
+}
+impl std::error::Error for BodyTooLarge {}
+
+/// `Content-Encoding` に応じてボディを展開する。ヘッダが無ければそのまま返す
+pub async fn decode_body(encoding: Option<&str>, bytes: Bytes, max_bytes: usize) -> Result<Bytes> {
+    let Some(encoding) = encoding else {
+        return Ok(bytes);
+    };
+    let reader = BufReader::new(&bytes[..]);
+    let decoded = match encoding {
+        "gzip" => read_capped(GzipDecoder::new(reader), max_bytes).await?,
+        "deflate" => read_capped(ZlibDecoder::new(reader), max_bytes).await?,
+        "br" => read_capped(BrotliDecoder::new(reader), max_bytes).await?,
+        other => return Err(anyhow!("unsupported Content-Encoding: {other}")),
+    };
+    Ok(Bytes::from(decoded))
+}
+
+// 展開後サイズが `max_bytes` を超えたら、読み切る前に打ち切ってエラーにする
+async fn read_capped<R: AsyncRead + Unpin>(mut r: R, max_bytes: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        let n = r.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > max_bytes {
+            return Err(BodyTooLarge { limit: max_bytes }.into());
+        }
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn read_capped_allows_exactly_the_limit() {
+        let data = vec![0u8; 50];
+        let out = read_capped(Cursor::new(data.clone()), 50).await.unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[tokio::test]
+    async fn read_capped_aborts_once_past_the_limit() {
+        let data = vec![0u8; 51];
+        let err = read_capped(Cursor::new(data), 50).await.unwrap_err();
+        assert!(err.downcast_ref::<BodyTooLarge>().is_some());
+    }
+}
@@ -0,0 +1,130 @@
+use crate::decompress::decode_body;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use hyper::body::Incoming;
+use hyper::Request;
+use http_body_util::BodyExt;
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+/// `/execute-wasm` への一回分の呼び出し入力。multipart で来なければ stdin/args/env は
+/// 空のまま実行する（= ホストの環境をそのまま guest に渡さない）
+pub struct Invocation {
+    pub wasm: Bytes,
+    pub stdin: Vec<u8>,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+}
+
+pub async fn parse_invocation(req: Request<Incoming>, max_decompressed: usize) -> Result<Invocation> {
+    let content_type = req
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let boundary = content_type.as_deref().and_then(|ct| multer::parse_boundary(ct).ok());
+
+    let encoding = req
+        .headers()
+        .get(hyper::header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let raw = req.into_body().collect().await?.to_bytes();
+
+    if let Some(boundary) = boundary {
+        parse_multipart(raw, boundary, encoding.as_deref(), max_decompressed).await
+    } else {
+        let wasm = decode_body(encoding.as_deref(), raw, max_decompressed).await?;
+        Ok(Invocation { wasm, stdin: Vec::new(), args: Vec::new(), env: Vec::new() })
+    }
+}
+
+// `Content-Encoding` はボディ全体にかかるヘッダで、multipart の境界も展開後のバイト列に
+// 対してしか意味を持たない。なので multipart としてパースする前に、まずボディ全体を
+// decode_body に通して展開してしまう（chunk0-4 の伸長上限もここでそのまま効く）
+async fn parse_multipart(
+    raw: Bytes,
+    boundary: String,
+    encoding: Option<&str>,
+    max_decompressed: usize,
+) -> Result<Invocation> {
+    let body = decode_body(encoding, raw, max_decompressed).await?;
+    let stream = futures_util::stream::once(async move { Ok::<Bytes, Infallible>(body) });
+    let mut multipart = multer::Multipart::new(stream, boundary);
+
+    let mut wasm = None;
+    let mut stdin = Vec::new();
+    let mut args = Vec::new();
+    let mut env = Vec::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("module") => wasm = Some(field.bytes().await?),
+            Some("stdin") => stdin = field.bytes().await?.to_vec(),
+            Some("args") => {
+                let text = field.text().await?;
+                args = serde_json::from_str::<Vec<String>>(&text)?;
+            }
+            Some("env") => {
+                let text = field.text().await?;
+                let map: HashMap<String, String> = serde_json::from_str(&text)?;
+                env = map.into_iter().collect();
+            }
+            _ => {}
+        }
+    }
+
+    let wasm = wasm.ok_or_else(|| anyhow!("multipart request is missing the `module` part"))?;
+    Ok(Invocation { wasm, stdin, args, env })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::write::GzipEncoder;
+    use tokio::io::AsyncWriteExt;
+
+    async fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder.write_all(bytes).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        encoder.into_inner()
+    }
+
+    fn multipart_body(boundary: &str, module: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+        body.extend_from_slice(b"Content-Disposition: form-data; name=\"module\"\r\n\r\n");
+        body.extend_from_slice(module);
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        body
+    }
+
+    #[tokio::test]
+    async fn multipart_module_is_decompressed_when_content_encoding_is_set() {
+        let boundary = "X-BOUNDARY";
+        let wasm = b"not real wasm, just bytes for the test";
+        let compressed = gzip(&multipart_body(boundary, wasm)).await;
+
+        let invocation =
+            parse_multipart(Bytes::from(compressed), boundary.to_string(), Some("gzip"), 1024 * 1024)
+                .await
+                .unwrap();
+
+        assert_eq!(invocation.wasm.as_ref(), wasm);
+    }
+
+    #[tokio::test]
+    async fn multipart_without_content_encoding_is_read_as_is() {
+        let boundary = "X-BOUNDARY";
+        let wasm = b"plain multipart body";
+        let raw = multipart_body(boundary, wasm);
+
+        let invocation = parse_multipart(Bytes::from(raw), boundary.to_string(), None, 1024 * 1024)
+            .await
+            .unwrap();
+
+        assert_eq!(invocation.wasm.as_ref(), wasm);
+    }
+}
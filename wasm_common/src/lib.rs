@@ -0,0 +1,8 @@
+//! Component (WASI Preview2) と Core Module (WASI Preview1) の両バイナリが共有する
+//! ロジック。Wasmtime の `Engine`/`Store` 型そのものには依存しない部分だけをここに置く
+
+pub mod cache;
+pub mod decompress;
+pub mod invocation;
+pub mod limits;
+pub mod report;
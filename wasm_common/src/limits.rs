@@ -0,0 +1,73 @@
+use anyhow::Result;
+
+fn env_usize(key: &str, default: usize) -> usize {
+    std::env::var(key).ok().and_then(|s| s.parse().ok()).unwrap_or(default)
+}
+
+/// guest 1 インスタンスあたりのメモリ/テーブルの上限。`memory.grow`/`table.grow` が
+/// この上限を超えようとしたら `false` を返し、guest 側には「成長失敗」として見せる
+/// （トラップではなく、guest が仕様通りハンドルできる失敗）
+pub struct Limits {
+    max_memory_bytes: usize,
+    max_table_elements: usize,
+    peak_memory_bytes: usize,
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        Self {
+            max_memory_bytes: env_usize("WASM_MAX_MEMORY_BYTES", 256 * 1024 * 1024),
+            max_table_elements: env_usize("WASM_MAX_TABLE_ELEMENTS", 10_000),
+            peak_memory_bytes: 0,
+        }
+    }
+
+    pub fn peak_memory_bytes(&self) -> u64 {
+        self.peak_memory_bytes as u64
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::from_env()
+    }
+}
+
+impl wasmtime::ResourceLimiter for Limits {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> Result<bool> {
+        let allowed = desired <= self.max_memory_bytes;
+        if allowed {
+            self.peak_memory_bytes = self.peak_memory_bytes.max(desired);
+        }
+        Ok(allowed)
+    }
+
+    fn table_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> Result<bool> {
+        Ok(desired <= self.max_table_elements)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::ResourceLimiter;
+
+    fn limits_with_max(max_memory_bytes: usize) -> Limits {
+        Limits { max_memory_bytes, max_table_elements: 0, peak_memory_bytes: 0 }
+    }
+
+    #[test]
+    fn allowed_grow_raises_the_peak() {
+        let mut limits = limits_with_max(1024);
+        assert!(limits.memory_growing(0, 512, None).unwrap());
+        assert_eq!(limits.peak_memory_bytes(), 512);
+    }
+
+    #[test]
+    fn denied_grow_is_rejected_and_does_not_move_the_peak() {
+        let mut limits = limits_with_max(1024);
+        assert!(limits.memory_growing(0, 512, None).unwrap());
+        assert!(!limits.memory_growing(0, 4096, None).unwrap());
+        assert_eq!(limits.peak_memory_bytes(), 512);
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hyper::StatusCode;
+use serde::Serialize;
+use wasmtime::Trap;
+
+/// `wasi:cli/run` が `Err(())` を返した場合の目印（トラップではなく guest 側の「失敗」通知）
+#[derive(Debug)]
+pub struct GuestFailure;
+impl std::fmt::Display for GuestFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "guest finished unsuccessfully")
+    }
+}
+impl std::error::Error for GuestFailure {}
+
+/// UTF-8 ならそのまま文字列で、そうでなければ base64 で表現する
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Output {
+    Text(String),
+    Base64 { base64: String },
+}
+
+fn encode_output(bytes: Vec<u8>) -> Output {
+    match String::from_utf8(bytes) {
+        Ok(s) => Output::Text(s),
+        Err(e) => Output::Base64 { base64: STANDARD.encode(e.into_bytes()) },
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExecutionReport {
+    pub stdout: Output,
+    pub stderr: Output,
+    pub exit_code: i32,
+    pub error: Option<String>,
+    pub fuel_consumed: Option<u64>,
+    pub duration_ms: u64,
+    pub peak_memory_bytes: u64,
+}
+
+/// guest の実行結果（wasi:cli run() の戻り値や `proc_exit`/epoch/fuel の trap）を、
+/// HTTP ステータスと JSON に出せる形へ正規化する
+pub fn classify(
+    result: Result<()>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    fuel_consumed: Option<u64>,
+    duration_ms: u64,
+    peak_memory_bytes: u64,
+) -> (StatusCode, ExecutionReport) {
+    let (status, exit_code, error) = match result {
+        Ok(()) => (StatusCode::OK, 0, None),
+        Err(e) => match e.downcast_ref::<wasmtime_wasi::I32Exit>() {
+            Some(exit) if exit.0 == 0 => (StatusCode::OK, 0, None),
+            Some(exit) => (StatusCode::UNPROCESSABLE_ENTITY, exit.0, Some(e.to_string())),
+            None if e.downcast_ref::<GuestFailure>().is_some() => {
+                (StatusCode::UNPROCESSABLE_ENTITY, 1, Some(e.to_string()))
+            }
+            None => match e.root_cause().downcast_ref::<Trap>() {
+                Some(Trap::Interrupt) => (StatusCode::GATEWAY_TIMEOUT, 1, Some(e.to_string())),
+                Some(Trap::OutOfFuel) => (StatusCode::REQUEST_TIMEOUT, 1, Some(e.to_string())),
+                _ => (StatusCode::BAD_REQUEST, 1, Some(e.to_string())),
+            },
+        },
+    };
+    (
+        status,
+        ExecutionReport {
+            stdout: encode_output(stdout),
+            stderr: encode_output(stderr),
+            exit_code,
+            error,
+            fuel_consumed,
+            duration_ms,
+            peak_memory_bytes,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_ok_is_200_with_no_error() {
+        let (status, report) = classify(Ok(()), vec![], vec![], None, 0, 0);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report.exit_code, 0);
+        assert!(report.error.is_none());
+    }
+
+    #[test]
+    fn classify_nonzero_proc_exit_is_422() {
+        let err = anyhow::Error::new(wasmtime_wasi::I32Exit(3));
+        let (status, report) = classify(Err(err), vec![], vec![], None, 0, 0);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(report.exit_code, 3);
+    }
+
+    #[test]
+    fn classify_guest_failure_is_422() {
+        let err = anyhow::Error::new(GuestFailure);
+        let (status, report) = classify(Err(err), vec![], vec![], None, 0, 0);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(report.exit_code, 1);
+    }
+
+    #[test]
+    fn classify_epoch_interrupt_is_504() {
+        let err = anyhow::Error::new(Trap::Interrupt);
+        let (status, _) = classify(Err(err), vec![], vec![], None, 0, 0);
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn classify_out_of_fuel_is_408() {
+        let err = anyhow::Error::new(Trap::OutOfFuel);
+        let (status, _) = classify(Err(err), vec![], vec![], None, 0, 0);
+        assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+    }
+}
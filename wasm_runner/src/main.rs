@@ -6,37 +6,140 @@ use hyper::service::service_fn;
 use hyper_util::rt::TokioExecutor;
 use hyper_util::server::conn::auto::Builder as AutoBuilder;
 use http_body_util::{Full, BodyExt};
-use std::{convert::Infallible, net::SocketAddr};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
 use tokio::select;
 
 // Wasmtime (Core Module用 - WASI Preview 1)
-use wasmtime::{Config, Engine, Module, Linker, Store};
+use wasmtime::{Config, Engine, Module, Linker, Store, Trap};
 use wasmtime_wasi::WasiCtxBuilder;
 use wasmtime_wasi::preview1::{WasiP1Ctx, add_to_linker_async};
 use wasmtime_wasi::p2::pipe::MemoryOutputPipe;
 
+// cache/decompress/invocation/limits/report は Component と Core Module の両バイナリで
+// 共有するロジックなので `wasm_common` クレートに切り出してある
+use wasm_common::cache::{self, CompileCache};
+use wasm_common::decompress::BodyTooLarge;
+use wasm_common::invocation::{parse_invocation, Invocation};
+use wasm_common::report::{classify, ExecutionReport};
+use wasm_common::limits::Limits;
+
+// 実行予算（epoch / fuel）の既定値。env で上書き可能
+const DEFAULT_EPOCH_TICK_MS: u64 = 10;
+const DEFAULT_EPOCH_DEADLINE_MS: u64 = 5_000;
+
+// `WasiP1Ctx` は wasmtime_wasi が提供する不透明な型なので、ResourceLimiter 用の状態は
+// 横に struct を足して Store のデータ型にする
+struct Ctx {
+    wasi: WasiP1Ctx,
+    limits: Limits,
+}
+
+fn env_u64(key: &str, default: u64) -> u64 {
+    std::env::var(key).ok().and_then(|s| s.parse().ok()).unwrap_or(default)
+}
+
+struct AppState {
+    engine: Engine,
+    // 燃料計測 (consume_fuel) は Engine 単位の設定なので、X-Wasm-Fuel 付きリクエスト専用に
+    // 別の Engine + キャッシュを常駐させておく。使い捨てにすると毎回フルコンパイルし直しになる
+    fuel_engine: Engine,
+    fuel_module_cache: CompileCache<[u8; 32], Module>,
+    module_cache: CompileCache<[u8; 32], Module>,
+}
+
+fn configure_reservations(cfg: &mut Config) {
+    // ★ 重要: 巨大な仮想領域予約を止める
+    // 予約サイズを小さく（例: 1 MiB）。初期サイズがこれより大きいとこの値は無視されます
+    cfg.memory_reservation(1 * 1024 * 1024);
+    // 成長用の追加予約も小さく（例: 16 MiB）
+    cfg.memory_reservation_for_growth(16 * 1024 * 1024);
+    // ガードページを使わない（予約をさらに節約）
+    cfg.memory_guard_size(0);
+    cfg.guard_before_linear_memory(false);
+    // 必要に応じて：成長時にメモリ移動を許可（予約が尽きたら移動）
+    cfg.memory_may_move(true);
+    // 64-bit メモリは無効のまま（既定で false）
+    cfg.wasm_memory64(false);
+}
+
+impl AppState {
+    fn new() -> Result<Self> {
+        let mut cfg = Config::new();
+        cfg.async_support(true);
+        cfg.epoch_interruption(true);
+        configure_reservations(&mut cfg);
+
+        let mut fuel_cfg = Config::new();
+        fuel_cfg.async_support(true);
+        fuel_cfg.epoch_interruption(true);
+        fuel_cfg.consume_fuel(true);
+        configure_reservations(&mut fuel_cfg);
+
+        let max_entries = env_u64("WASM_CACHE_MAX_ENTRIES", 64) as usize;
+        let max_bytes = env_u64("WASM_CACHE_MAX_BYTES", 256 * 1024 * 1024) as usize;
+        Ok(Self {
+            engine: Engine::new(&cfg)?,
+            fuel_engine: Engine::new(&fuel_cfg)?,
+            fuel_module_cache: CompileCache::new(max_entries, max_bytes),
+            module_cache: CompileCache::new(max_entries, max_bytes),
+        })
+    }
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
     let host = std::env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
     let port: u16 = std::env::var("PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(3000);
     let addr: SocketAddr = format!("{}:{}", host, port).parse().expect("invalid HOST/PORT");
 
+    let state = Arc::new(AppState::new()?);
+
     let listener = TcpListener::bind(addr).await?;
     println!("listening on http://{}", addr);
 
+    // epoch を wall-clock にマッピングする tick タスク。プロセス全体で一つだけ動かす
+    let tick_ms = env_u64("WASM_EPOCH_TICK_MS", DEFAULT_EPOCH_TICK_MS).max(1);
+    let ticker_engine = state.engine.clone();
+    let ticker_fuel_engine = state.fuel_engine.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(tick_ms));
+        loop {
+            interval.tick().await;
+            ticker_engine.increment_epoch();
+            ticker_fuel_engine.increment_epoch();
+        }
+    });
+
+    // Ctrl+C で新規受付を止めた後、既存接続へ graceful shutdown を知らせるための合図
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let mut conns = tokio::task::JoinSet::new();
+
     use hyper_util::rt::TokioIo;
     select! {
         res = async {
             loop {
                 let (io, _peer) = listener.accept().await?;
-                tokio::spawn(async move {
-                    let svc = service_fn(router);
+                let state = state.clone();
+                let mut shutdown_rx = shutdown_rx.clone();
+                conns.spawn(async move {
+                    let svc = service_fn(move |req| router(state.clone(), req));
                     let io = TokioIo::new(io);
                     let builder = AutoBuilder::new(TokioExecutor::new());
                     let conn = builder.serve_connection(io, svc);
-                    if let Err(e) = conn.await {
-                        eprintln!("server error: {e}");
+                    tokio::pin!(conn);
+                    tokio::select! {
+                        res = conn.as_mut() => {
+                            if let Err(e) = res {
+                                eprintln!("server error: {e}");
+                            }
+                        }
+                        _ = shutdown_rx.changed() => {
+                            conn.as_mut().graceful_shutdown();
+                            if let Err(e) = conn.await {
+                                eprintln!("server error during graceful shutdown: {e}");
+                            }
+                        }
                     }
                 });
             }
@@ -46,13 +149,28 @@ async fn main() -> anyhow::Result<()> {
             res?;
         },
         _ = tokio::signal::ctrl_c() => {
-            eprintln!("Ctrl+C received. stopping the server");
+            eprintln!("Ctrl+C received. draining in-flight connections...");
         }
     }
+
+    // 新規接続の受付はここで止まっている。既存接続には graceful shutdown を通知し、
+    // drain timeout まで待ってから終了する（待ちきれない分は打ち切る）
+    let _ = shutdown_tx.send(true);
+    let drain_timeout = Duration::from_millis(env_u64("SHUTDOWN_DRAIN_TIMEOUT_MS", 30_000));
+    if tokio::time::timeout(drain_timeout, async {
+        while conns.join_next().await.is_some() {}
+    })
+    .await
+    .is_err()
+    {
+        eprintln!("drain timeout exceeded; aborting {} remaining connection(s)", conns.len());
+        conns.shutdown().await;
+    }
+
     Ok(())
 }
 
-async fn router(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+async fn router(state: Arc<AppState>, req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
     let method = req.method().clone();
     let path = req.uri().path().to_string();
 
@@ -61,9 +179,10 @@ async fn router(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infalli
             ok_text("OK: POST /execute-wasm (body = WASI Core Module)")
         }
         (Method::POST, "/execute-wasm") => {
-            match handle_execute_wasm(req).await {
-                Ok(text) => ok_text(text),
-                Err(e)   => err_text(StatusCode::BAD_REQUEST, format!("WASM error: {e}")),
+            let wants_json = accepts_json(&req);
+            match handle_execute_wasm(&state, req).await {
+                Ok((status, report, cache_status)) => execution_response(status, report, cache_status, wants_json),
+                Err(e)   => execution_error_response(&e),
             }
         }
         _ => err_text(StatusCode::NOT_FOUND, "not found"),
@@ -88,30 +207,146 @@ fn err_text<S: Into<String>>(code: StatusCode, s: S) -> Response<Full<Bytes>> {
         .unwrap()
 }
 
-async fn handle_execute_wasm(req: Request<Incoming>) -> Result<String> {
-    let bytes = req.into_body().collect().await?.to_bytes();
+fn accepts_json(req: &Request<Incoming>) -> bool {
+    req.headers()
+        .get(hyper::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"))
+}
+
+// Accept: application/json なら構造化した実行結果を、それ以外は従来どおりの素のテキストを返す
+fn execution_response(
+    status: StatusCode,
+    report: ExecutionReport,
+    cache_status: &str,
+    wants_json: bool,
+) -> Response<Full<Bytes>> {
+    if wants_json {
+        let body = serde_json::to_vec(&report).expect("ExecutionReport always serializes");
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .header("x-cache", cache_status)
+            .body(Full::from(Bytes::from(body)))
+            .unwrap()
+    } else {
+        let text = match &report.error {
+            Some(e) => format!("WASM error: {e}"),
+            None => format_stdio(&report),
+        };
+        Response::builder()
+            .status(status)
+            .header("content-type", "text/plain; charset=utf-8")
+            .header("x-cache", cache_status)
+            .body(Full::from(Bytes::from(text)))
+            .unwrap()
+    }
+}
 
-    let mut cfg = Config::new();
-    cfg.async_support(true);
+// 元々の素のテキスト形式（stderr が無ければ stdout だけ）を `ExecutionReport` から再現する
+fn format_stdio(report: &ExecutionReport) -> String {
+    let out = output_as_text(&report.stdout);
+    let err = output_as_text(&report.stderr);
+    if err.is_empty() {
+        out
+    } else {
+        format!("-- stdout --\n{out}\n\n-- stderr --\n{err}")
+    }
+}
 
-    // ★ 重要: 巨大な仮想領域予約を止める
-    // 予約サイズを小さく（例: 1 MiB）。初期サイズがこれより大きいとこの値は無視されます
-    cfg.memory_reservation(1 * 1024 * 1024);
-    // 成長用の追加予約も小さく（例: 16 MiB）
-    cfg.memory_reservation_for_growth(16 * 1024 * 1024);
-    // ガードページを使わない（予約をさらに節約）
-    cfg.memory_guard_size(0);
-    cfg.guard_before_linear_memory(false);
-    // 必要に応じて：成長時にメモリ移動を許可（予約が尽きたら移動）
-    cfg.memory_may_move(true);
-    // 64-bit メモリは無効のまま（既定で false）
-    cfg.wasm_memory64(false);
+fn output_as_text(output: &wasm_common::report::Output) -> String {
+    match output {
+        wasm_common::report::Output::Text(s) => s.clone(),
+        wasm_common::report::Output::Base64 { base64 } => format!("(base64) {base64}"),
+    }
+}
 
-    let engine = Engine::new(&cfg)?;
-    let module = Module::from_binary(&engine, &bytes)?;
+// epoch 割り込み／燃料切れは「ただのバグ」とは違うので、普通の trap (400) とは別の
+// ステータスで区別できるようにする
+fn execution_error_response(e: &anyhow::Error) -> Response<Full<Bytes>> {
+    if let Some(e) = e.downcast_ref::<BodyTooLarge>() {
+        return err_text(StatusCode::PAYLOAD_TOO_LARGE, format!("WASM error: {e}"));
+    }
+    match e.root_cause().downcast_ref::<Trap>() {
+        Some(Trap::Interrupt) => {
+            err_text(StatusCode::GATEWAY_TIMEOUT, format!("WASM error: execution deadline exceeded: {e}"))
+        }
+        Some(Trap::OutOfFuel) => {
+            err_text(StatusCode::REQUEST_TIMEOUT, format!("WASM error: fuel budget exhausted: {e}"))
+        }
+        _ => err_text(StatusCode::BAD_REQUEST, format!("WASM error: {e}")),
+    }
+}
+
+async fn handle_execute_wasm(
+    state: &AppState,
+    req: Request<Incoming>,
+) -> Result<(StatusCode, ExecutionReport, &'static str)> {
+    // X-Wasm-Fuel: このリクエスト限りの命令数上限（未指定なら env の既定値、それも無ければ無制限）
+    let fuel_limit = req
+        .headers()
+        .get("x-wasm-fuel")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+        .or_else(|| {
+            let v = env_u64("WASM_FUEL_DEFAULT", 0);
+            if v > 0 { Some(v) } else { None }
+        });
+
+    // 非 multipart なら本文全体を wasm として扱う（Content-Encoding があれば展開）。
+    // multipart/form-data なら module/stdin/args/env の各パートを読み取る
+    let max_decompressed = env_u64("WASM_MAX_DECOMPRESSED_BYTES", 64 * 1024 * 1024) as usize;
+    let Invocation { wasm: bytes, stdin, args, env } = parse_invocation(req, max_decompressed).await?;
+
+    let tick_ms = env_u64("WASM_EPOCH_TICK_MS", DEFAULT_EPOCH_TICK_MS).max(1);
+    let deadline_ms = env_u64("WASM_EPOCH_DEADLINE_MS", DEFAULT_EPOCH_DEADLINE_MS);
+    let deadline_ticks = (deadline_ms / tick_ms).max(1);
+
+    if fuel_limit.is_some() {
+        // 燃料消費の計測は、燃料計測を有効にした専用の常駐 Engine + キャッシュで行う
+        let key = cache::content_hash(&bytes);
+        let (module, cache_status) = match state.fuel_module_cache.get(&key) {
+            Some(module) => (module, "hit"),
+            None => {
+                let serialized = state.fuel_engine.precompile_module(&bytes)?;
+                let weight = serialized.len();
+                let module = unsafe { Module::deserialize(&state.fuel_engine, &serialized)? };
+                state.fuel_module_cache.insert(key, module.clone(), weight);
+                (module, "miss")
+            }
+        };
+        let (status, report) =
+            run_module(&state.fuel_engine, &module, deadline_ticks, fuel_limit, &stdin, &args, &env).await?;
+        Ok((status, report, cache_status))
+    } else {
+        let key = cache::content_hash(&bytes);
+        let (module, cache_status) = match state.module_cache.get(&key) {
+            Some(module) => (module, "hit"),
+            None => {
+                // コンパイルを一度だけ行い、そのシリアライズ済みサイズをキャッシュの重みに使う
+                let serialized = state.engine.precompile_module(&bytes)?;
+                let weight = serialized.len();
+                let module = unsafe { Module::deserialize(&state.engine, &serialized)? };
+                state.module_cache.insert(key, module.clone(), weight);
+                (module, "miss")
+            }
+        };
+        let (status, report) = run_module(&state.engine, &module, deadline_ticks, fuel_limit, &stdin, &args, &env).await?;
+        Ok((status, report, cache_status))
+    }
+}
 
-    let mut linker = Linker::new(&engine);
-    add_to_linker_async(&mut linker, |t: &mut WasiP1Ctx| t)?;
+async fn run_module(
+    engine: &Engine,
+    module: &Module,
+    deadline_ticks: u64,
+    fuel_limit: Option<u64>,
+    stdin: &[u8],
+    args: &[String],
+    env: &[(String, String)],
+) -> Result<(StatusCode, ExecutionReport)> {
+    let mut linker = Linker::new(engine);
+    add_to_linker_async(&mut linker, |t: &mut Ctx| &mut t.wasi)?;
 
     // ★ 容量は十分に（例: 1MB）。0 は “無制限” ではありません
     let stdout_pipe = MemoryOutputPipe::new(1 * 1024 * 1024);
@@ -121,20 +356,42 @@ async fn handle_execute_wasm(req: Request<Incoming>) -> Result<String> {
     let stdout_reader = stdout_pipe.clone();
     let stderr_reader = stderr_pipe.clone();
 
+    // 呼び出し元が渡した stdin/args/env をそのまま使う。ホストの環境は渡さない
     let wasi = WasiCtxBuilder::new()
+        .stdin(wasmtime_wasi::p2::pipe::MemoryInputPipe::new(stdin.to_vec()))
+        .args(args)
+        .envs(env)
         .stdout(stdout_pipe)  // ← 本体を move
         .stderr(stderr_pipe)  // ← 本体を move
         .build_p1();
 
-    let mut store = Store::new(&engine, wasi);
-    let instance = linker.instantiate_async(&mut store, &module).await?;
-    let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
-    start.call_async(&mut store, ()).await?;
+    let mut store = Store::new(engine, Ctx { wasi, limits: Limits::from_env() });
+    store.set_epoch_deadline(deadline_ticks);
+    if let Some(limit) = fuel_limit {
+        store.set_fuel(limit)?;
+    }
+    store.limiter(|ctx| &mut ctx.limits);
 
-    // ★ 実行完了後に “reader” 側から中身を読む
-    let out = String::from_utf8_lossy(&stdout_reader.contents()).to_string();
-    let err = String::from_utf8_lossy(&stderr_reader.contents()).to_string();
+    let start_time = std::time::Instant::now();
+    // Preview1 の `_start` は wasi:cli/run と違って `Err(())` 相当の「失敗通知」を持たず、
+    // 失敗は常に trap (proc_exit を含む) として現れる
+    let result: Result<()> = async {
+        let instance = linker.instantiate_async(&mut store, module).await?;
+        let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+        start.call_async(&mut store, ()).await
+    }
+    .await;
+    let duration_ms = start_time.elapsed().as_millis() as u64;
+    let fuel_consumed = fuel_limit.map(|limit| limit.saturating_sub(store.get_fuel().unwrap_or(0)));
+    let peak_memory_bytes = store.data().limits.peak_memory_bytes();
 
-    Ok(if err.is_empty() { out } else { format!("-- stdout --\n{out}\n\n-- stderr --\n{err}") })
+    // ★ 実行完了後に “reader” 側から中身を読む
+    Ok(classify(
+        result,
+        stdout_reader.contents().to_vec(),
+        stderr_reader.contents().to_vec(),
+        fuel_consumed,
+        duration_ms,
+        peak_memory_bytes,
+    ))
 }
-